@@ -2,15 +2,272 @@ use serde_json;
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
-use std::time::Instant;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tch::{CModule, Device, Kind, Tensor};
+use tokenizers::Tokenizer;
 
 // Constants
 const DEFAULT_WORK_DIR: &str = "work";
 const DEFAULT_TEST_DATA: &str = "test/input.txt";
 const DEFAULT_TEST_OUTPUT: &str = "pred.txt";
 const CONTEXT_LENGTH: i64 = 32;
+const DEFAULT_BATCH_SIZE: i64 = 64;
+const DEFAULT_MAX_TOKENS: i64 = 4096;
+const DEFAULT_CACHE_SIZE: usize = 10_000;
+const DEFAULT_NUM_PREDS: i64 = 3;
+const DEFAULT_TEMPERATURE: f64 = 1.0;
+const DEFAULT_TOP_P: f64 = 0.9;
+// Floor applied to `--temperature` before dividing logits by it. A user-supplied `0.0` (a
+// plausible attempt at "almost deterministic" sampling) would otherwise push masked logits to
+// +/-inf and softmax that to NaN, leaving `sample_distinct` no usable probabilities to draw
+// from, so rows would silently come back without predictions instead of sampling or erroring.
+const MIN_TEMPERATURE: f64 = 1e-4;
+// Bound on in-flight batches between pipeline stages, so a slow stage applies backpressure
+// to the ones ahead of it instead of the whole file queuing up in memory.
+const PIPELINE_CHANNEL_CAPACITY: usize = 2;
+
+// Decoding strategy used to turn a batch's logits into `num_preds` predicted characters.
+#[derive(Clone, Copy)]
+enum DecodeMode {
+    Greedy,
+    Temperature,
+    Nucleus,
+}
+
+fn parse_decode_mode(s: &str) -> DecodeMode {
+    match s {
+        "temperature" => DecodeMode::Temperature,
+        "nucleus" => DecodeMode::Nucleus,
+        _ => DecodeMode::Greedy,
+    }
+}
+
+// Which device to run on, as requested on the command line. `Auto` is resolved to a concrete
+// `tch::Device` once in `main` so the rest of the pipeline only ever deals with one device.
+#[derive(Clone, Copy)]
+enum DeviceChoice {
+    Cpu,
+    Cuda,
+    Auto,
+}
+
+fn parse_device_choice(s: &str) -> DeviceChoice {
+    match s {
+        "cpu" => DeviceChoice::Cpu,
+        "cuda" => DeviceChoice::Cuda,
+        _ => DeviceChoice::Auto,
+    }
+}
+
+fn resolve_device(choice: DeviceChoice) -> Device {
+    match choice {
+        DeviceChoice::Cpu => Device::Cpu,
+        DeviceChoice::Cuda => Device::Cuda,
+        DeviceChoice::Auto => {
+            if tch::Cuda::is_available() {
+                Device::Cuda
+            } else {
+                Device::Cpu
+            }
+        }
+    }
+}
+
+// Upper bounds (in seconds) of the latency histogram buckets below, with an implicit
+// trailing "+Inf" bucket, matching the Prometheus exposition-format convention.
+const LATENCY_BUCKET_BOUNDS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+// A minimal Prometheus-style cumulative histogram, built from atomics so any pipeline stage
+// can observe a value without taking a lock.
+struct Histogram {
+    bucket_counts: Vec<AtomicU64>,
+    sum_micros: AtomicU64,
+    count: AtomicU64,
+}
+
+impl Histogram {
+    fn new() -> Self {
+        Histogram {
+            bucket_counts: LATENCY_BUCKET_BOUNDS.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_micros: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        }
+    }
+
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKET_BOUNDS.iter().zip(&self.bucket_counts) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.sum_micros.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn render(&self, name: &str, help: &str, out: &mut String) {
+        out.push_str(&format!("# HELP {} {}\n", name, help));
+        out.push_str(&format!("# TYPE {} histogram\n", name));
+        for (bound, bucket) in LATENCY_BUCKET_BOUNDS.iter().zip(&self.bucket_counts) {
+            out.push_str(&format!(
+                "{}_bucket{{le=\"{}\"}} {}\n",
+                name,
+                bound,
+                bucket.load(Ordering::Relaxed)
+            ));
+        }
+        let total = self.count.load(Ordering::Relaxed);
+        out.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", name, total));
+        out.push_str(&format!(
+            "{}_sum {}\n",
+            name,
+            self.sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        ));
+        out.push_str(&format!("{}_count {}\n", name, total));
+    }
+}
+
+// Process-wide counters and histograms for the inference loop, exposed in Prometheus text
+// exposition format by the optional `--metrics_port` HTTP endpoint. Always constructed, even
+// when no port is given, so the pipeline stages never need to special-case metrics being
+// absent; a handful of atomics costs nothing to keep around.
+struct Metrics {
+    examples_total: AtomicU64,
+    batches_total: AtomicU64,
+    batch_size_sum: AtomicU64,
+    cache_hits_total: AtomicU64,
+    cache_misses_total: AtomicU64,
+    pad_fallbacks_total: AtomicU64,
+    inference_latency: Histogram,
+    post_processing_latency: Histogram,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        Metrics {
+            examples_total: AtomicU64::new(0),
+            batches_total: AtomicU64::new(0),
+            batch_size_sum: AtomicU64::new(0),
+            cache_hits_total: AtomicU64::new(0),
+            cache_misses_total: AtomicU64::new(0),
+            pad_fallbacks_total: AtomicU64::new(0),
+            inference_latency: Histogram::new(),
+            post_processing_latency: Histogram::new(),
+        }
+    }
+
+    fn record_example(&self) {
+        self.examples_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_batch(&self, size: usize) {
+        self.batches_total.fetch_add(1, Ordering::Relaxed);
+        self.batch_size_sum.fetch_add(size as u64, Ordering::Relaxed);
+    }
+
+    fn record_cache_hit(&self) {
+        self.cache_hits_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_cache_miss(&self) {
+        self.cache_misses_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record_pad_fallback(&self) {
+        self.pad_fallbacks_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    // Render every metric in Prometheus text exposition format.
+    fn render(&self) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP predict_examples_total Total input lines processed.\n");
+        out.push_str("# TYPE predict_examples_total counter\n");
+        out.push_str(&format!(
+            "predict_examples_total {}\n",
+            self.examples_total.load(Ordering::Relaxed)
+        ));
+
+        let batches = self.batches_total.load(Ordering::Relaxed);
+        out.push_str("# HELP predict_batches_total Total batches flushed through the model.\n");
+        out.push_str("# TYPE predict_batches_total counter\n");
+        out.push_str(&format!("predict_batches_total {}\n", batches));
+
+        let avg_batch_size = if batches == 0 {
+            0.0
+        } else {
+            self.batch_size_sum.load(Ordering::Relaxed) as f64 / batches as f64
+        };
+        out.push_str("# HELP predict_batch_size_average Average rows per flushed batch.\n");
+        out.push_str("# TYPE predict_batch_size_average gauge\n");
+        out.push_str(&format!("predict_batch_size_average {}\n", avg_batch_size));
+
+        let hits = self.cache_hits_total.load(Ordering::Relaxed);
+        let misses = self.cache_misses_total.load(Ordering::Relaxed);
+        let hit_ratio = if hits + misses == 0 {
+            0.0
+        } else {
+            hits as f64 / (hits + misses) as f64
+        };
+        out.push_str("# HELP predict_cache_hit_ratio Suffix-prediction cache hit rate.\n");
+        out.push_str("# TYPE predict_cache_hit_ratio gauge\n");
+        out.push_str(&format!("predict_cache_hit_ratio {}\n", hit_ratio));
+
+        out.push_str(
+            "# HELP predict_pad_fallbacks_total Out-of-vocabulary characters resolved to the pad token.\n",
+        );
+        out.push_str("# TYPE predict_pad_fallbacks_total counter\n");
+        out.push_str(&format!(
+            "predict_pad_fallbacks_total {}\n",
+            self.pad_fallbacks_total.load(Ordering::Relaxed)
+        ));
+
+        self.inference_latency.render(
+            "predict_inference_latency_seconds",
+            "Per-batch model forward-pass latency.",
+            &mut out,
+        );
+        self.post_processing_latency.render(
+            "predict_post_processing_latency_seconds",
+            "Per-batch decode/post-processing latency.",
+            &mut out,
+        );
+
+        out
+    }
+}
+
+// Serve `/metrics` (any path is accepted; there's only one route) in Prometheus text
+// exposition format on a background thread for as long as the process is running, so a
+// batch-prediction run can be scraped like a long-lived service.
+fn spawn_metrics_server(port: u16, metrics: Arc<Metrics>) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            handle_metrics_request(stream, &metrics);
+        }
+    });
+    Ok(())
+}
+
+fn handle_metrics_request(mut stream: TcpStream, metrics: &Metrics) {
+    let mut discard = [0u8; 1024];
+    let _ = stream.read(&mut discard); // we don't parse the request, there's only one route
+
+    let body = metrics.render();
+    let response = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        body.len(),
+        body
+    );
+    let _ = stream.write_all(response.as_bytes());
+}
 
 // Struct to hold command line arguments
 struct Args {
@@ -19,6 +276,16 @@ struct Args {
     test_output: String,
     time: bool,
     torchscript: bool,
+    batch_size: i64,
+    max_tokens: i64,
+    cache_size: usize,
+    decode: DecodeMode,
+    num_preds: i64,
+    temperature: f64,
+    top_p: f64,
+    device: DeviceChoice,
+    tokenizer_path: Option<String>,
+    metrics_port: Option<u16>,
 }
 
 // Parse command line arguments
@@ -29,6 +296,16 @@ fn parse_args() -> Args {
     let mut test_output = String::from(DEFAULT_TEST_OUTPUT);
     let mut time = false;
     let mut torchscript = false;
+    let mut batch_size = DEFAULT_BATCH_SIZE;
+    let mut max_tokens = DEFAULT_MAX_TOKENS;
+    let mut cache_size = DEFAULT_CACHE_SIZE;
+    let mut decode = DecodeMode::Greedy;
+    let mut num_preds = DEFAULT_NUM_PREDS;
+    let mut temperature = DEFAULT_TEMPERATURE;
+    let mut top_p = DEFAULT_TOP_P;
+    let mut device = DeviceChoice::Auto;
+    let mut tokenizer_path: Option<String> = None;
+    let mut metrics_port: Option<u16> = None;
 
     let mut i = 1;
     while i < args.len() {
@@ -57,6 +334,86 @@ fn parse_args() -> Args {
                     i += 1;
                 }
             }
+            "--batch_size" => {
+                if i + 1 < args.len() {
+                    batch_size = args[i + 1].parse().unwrap_or(DEFAULT_BATCH_SIZE);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--max_tokens" => {
+                if i + 1 < args.len() {
+                    max_tokens = args[i + 1].parse().unwrap_or(DEFAULT_MAX_TOKENS);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--cache_size" => {
+                if i + 1 < args.len() {
+                    cache_size = args[i + 1].parse().unwrap_or(DEFAULT_CACHE_SIZE);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--decode" => {
+                if i + 1 < args.len() {
+                    decode = parse_decode_mode(&args[i + 1]);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--num_preds" => {
+                if i + 1 < args.len() {
+                    num_preds = args[i + 1].parse().unwrap_or(DEFAULT_NUM_PREDS);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--temperature" => {
+                if i + 1 < args.len() {
+                    temperature = args[i + 1].parse().unwrap_or(DEFAULT_TEMPERATURE);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--top_p" => {
+                if i + 1 < args.len() {
+                    top_p = args[i + 1].parse().unwrap_or(DEFAULT_TOP_P);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--device" => {
+                if i + 1 < args.len() {
+                    device = parse_device_choice(&args[i + 1]);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--tokenizer" => {
+                if i + 1 < args.len() {
+                    tokenizer_path = Some(args[i + 1].clone());
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
+            "--metrics_port" => {
+                if i + 1 < args.len() {
+                    metrics_port = args[i + 1].parse().ok();
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+            }
             "--time" => {
                 time = true;
                 i += 1;
@@ -78,11 +435,21 @@ fn parse_args() -> Args {
         test_output,
         time,
         torchscript,
+        batch_size,
+        max_tokens,
+        cache_size,
+        decode,
+        num_preds,
+        temperature,
+        top_p,
+        device,
+        tokenizer_path,
+        metrics_port,
     }
 }
 
-// Load vocabulary from JSON file
-fn load_vocab(vocab_path: &str) -> io::Result<(HashMap<String, i64>, Vec<String>)> {
+// Load the character-level vocabulary from JSON file
+fn load_char_vocab(vocab_path: &str) -> io::Result<(HashMap<String, i64>, Vec<String>)> {
     println!("Loading vocabulary from {}", vocab_path);
     let start = Instant::now();
 
@@ -107,41 +474,79 @@ fn load_vocab(vocab_path: &str) -> io::Result<(HashMap<String, i64>, Vec<String>
     Ok((vocab_map, index_to_char))
 }
 
-// Load test input data efficiently
-fn load_test_input(file_path: &str) -> io::Result<Vec<String>> {
+// Stream test input lines one at a time instead of materializing the whole file, so the
+// batching executor can bound memory regardless of input size.
+fn load_test_input(file_path: &str) -> io::Result<impl Iterator<Item = io::Result<String>>> {
     println!("Loading test data from {}", file_path);
-    let start = Instant::now();
 
     let file = File::open(file_path)?;
     let metadata = fs::metadata(file_path)?;
-    let file_size = metadata.len();
+    println!("File size: {} bytes", metadata.len());
 
-    println!("File size: {} bytes", file_size);
-
-    // Process the file efficiently
     let reader = BufReader::with_capacity(8 * 1024 * 1024, file); // 8MB buffer
-    let lines: Vec<String> = reader.lines().filter_map(Result::ok).collect();
-
-    let duration = start.elapsed();
-    println!("Loaded {} lines in {:.2?}", lines.len(), duration);
+    Ok(reader.lines())
+}
 
-    Ok(lines)
+// A loaded vocabulary: either the default character-level lookup, or an optional HuggingFace
+// subword/BPE tokenizer loaded from a `tokenizer.json`. Both expose the same encode/decode
+// surface so the batching and prediction logic doesn't need to know which backend is active.
+enum Vocab {
+    Char {
+        char_to_index: HashMap<String, i64>,
+        index_to_char: Vec<String>,
+    },
+    Subword {
+        tokenizer: Tokenizer,
+    },
 }
 
-// Embed strings for the model
-fn embed_strings(inputs: &[String], vocab: &HashMap<String, i64>, pad_token: i64) -> Tensor {
-    let batch_size = inputs.len() as i64;
-    let mut encoded = vec![pad_token; (batch_size * CONTEXT_LENGTH) as usize];
+impl Vocab {
+    fn pad_token(&self) -> i64 {
+        match self {
+            Vocab::Char { char_to_index, .. } => {
+                *char_to_index.get(" ").expect("No padding token in vocabulary")
+            }
+            Vocab::Subword { tokenizer } => tokenizer
+                .token_to_id("[PAD]")
+                .map(|id| id as i64)
+                .unwrap_or(0),
+        }
+    }
 
-    for (i, s) in inputs.iter().enumerate() {
-        let mut indices: Vec<i64> = s
-            .chars()
-            .rev()
-            .take(CONTEXT_LENGTH as usize)
-            .map(|c| *vocab.get(&c.to_string()).unwrap_or(&pad_token))
-            .collect();
+    // Encode a line into the fixed-width, left-padded context the model expects, taking the
+    // last CONTEXT_LENGTH characters (or tokens) exactly as the char path always has. This is
+    // also the cache key for the suffix-prediction cache, since the model only ever sees these
+    // CONTEXT_LENGTH indices.
+    fn encode_context(&self, s: &str, pad_token: i64, metrics: &Metrics) -> Vec<i64> {
+        let mut indices: Vec<i64> = match self {
+            Vocab::Char { char_to_index, .. } => s
+                .chars()
+                .rev()
+                .take(CONTEXT_LENGTH as usize)
+                .map(|c| match char_to_index.get(&c.to_string()) {
+                    Some(&idx) => idx,
+                    None => {
+                        // Out-of-vocabulary character: fall back to the pad token, same as
+                        // before, but also count it so `--metrics_port` can surface how often
+                        // this happens.
+                        metrics.record_pad_fallback();
+                        pad_token
+                    }
+                })
+                .collect(),
+            Vocab::Subword { tokenizer } => {
+                let encoding = tokenizer.encode(s, false).expect("tokenizer encode failed");
+                encoding
+                    .get_ids()
+                    .iter()
+                    .rev()
+                    .take(CONTEXT_LENGTH as usize)
+                    .map(|&id| id as i64)
+                    .collect()
+            }
+        };
 
-        indices.reverse(); // We took chars in reverse, now flip back
+        indices.reverse(); // We took tokens in reverse, now flip back
 
         let padding_needed = CONTEXT_LENGTH as usize - indices.len();
         if padding_needed > 0 {
@@ -149,30 +554,211 @@ fn embed_strings(inputs: &[String], vocab: &HashMap<String, i64>, pad_token: i64
             indices = [padding, indices].concat();
         }
 
-        // Copy to our flat array
+        indices
+    }
+
+    // Map a predicted token id back to its surface string: a single character on the char
+    // path, a subword piece on the tokenizer path.
+    fn decode_token(&self, id: i64) -> String {
+        match self {
+            Vocab::Char { index_to_char, .. } => index_to_char[id as usize].clone(),
+            Vocab::Subword { tokenizer } => tokenizer.id_to_token(id as u32).unwrap_or_default(),
+        }
+    }
+
+    fn size(&self) -> i64 {
+        match self {
+            Vocab::Char { index_to_char, .. } => index_to_char.len() as i64,
+            Vocab::Subword { tokenizer } => tokenizer.get_vocab_size(true) as i64,
+        }
+    }
+}
+
+// Embed already-encoded contexts into a preallocated flat buffer, reused across batches so
+// the batching executor doesn't reallocate on every flush.
+fn embed_contexts(contexts: &[Vec<i64>], pad_token: i64, buffer: &mut Vec<i64>, device: Device) -> Tensor {
+    let batch_size = contexts.len() as i64;
+    buffer.clear();
+    buffer.resize((batch_size * CONTEXT_LENGTH) as usize, pad_token);
+
+    for (i, indices) in contexts.iter().enumerate() {
+        // Copy to our flat buffer
         for (j, &idx) in indices.iter().enumerate().take(CONTEXT_LENGTH as usize) {
-            encoded[i * CONTEXT_LENGTH as usize + j] = idx;
+            buffer[i * CONTEXT_LENGTH as usize + j] = idx;
         }
     }
 
-    Tensor::of_slice(&encoded)
+    Tensor::of_slice(buffer.as_slice())
         .reshape(&[batch_size, CONTEXT_LENGTH])
-        .to(Device::Cuda)
+        .to(device)
+}
+
+// Sentinel used in place of `Option<usize>` for the intrusive list links below, so the hot path
+// (`get`/`insert`) never has to branch on `Option`.
+const NIL: usize = usize::MAX;
+
+// One slot in `PredictionCache`'s node slab, doubling as a node in the intrusive
+// most-recently-used-to-least-recently-used doubly-linked list.
+struct CacheNode {
+    key: Vec<i64>,
+    prediction: String,
+    prev: usize,
+    next: usize,
+}
+
+// LRU cache mapping an encoded context to its already-computed prediction, so repeated
+// CONTEXT_LENGTH-length suffixes in a file skip redundant forward passes. `index` maps a key to
+// its slot in `nodes`; `nodes` doubles as a slab (freed slots are recycled via `free`) and an
+// intrusive doubly-linked list ordered most- to least-recently-used, so both a cache hit's
+// move-to-front and eviction of the least-recently-used entry are O(1) regardless of cache size.
+struct PredictionCache {
+    capacity: usize,
+    index: HashMap<Vec<i64>, usize>,
+    nodes: Vec<CacheNode>,
+    free: Vec<usize>,
+    head: usize, // most-recently-used node, or NIL if empty
+    tail: usize, // least-recently-used node, or NIL if empty
+    hits: u64,
+    misses: u64,
+}
+
+impl PredictionCache {
+    fn new(capacity: usize) -> Self {
+        PredictionCache {
+            capacity,
+            index: HashMap::new(),
+            nodes: Vec::new(),
+            free: Vec::new(),
+            head: NIL,
+            tail: NIL,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    fn get(&mut self, key: &[i64]) -> Option<String> {
+        match self.index.get(key) {
+            Some(&node) => {
+                let prediction = self.nodes[node].prediction.clone();
+                self.move_to_front(node);
+                self.hits += 1;
+                Some(prediction)
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    fn insert(&mut self, key: Vec<i64>, prediction: String) {
+        if self.capacity == 0 || self.index.contains_key(&key) {
+            return;
+        }
+
+        if self.index.len() >= self.capacity {
+            self.evict_lru();
+        }
+
+        let node = match self.free.pop() {
+            Some(node) => {
+                self.nodes[node].key = key.clone();
+                self.nodes[node].prediction = prediction;
+                node
+            }
+            None => {
+                self.nodes.push(CacheNode {
+                    key: key.clone(),
+                    prediction,
+                    prev: NIL,
+                    next: NIL,
+                });
+                self.nodes.len() - 1
+            }
+        };
+
+        self.index.insert(key, node);
+        self.push_front(node);
+    }
+
+    // Detach `node` from wherever it sits in the list and reinsert it at the front (the
+    // most-recently-used position), marking it most-recently-used.
+    fn move_to_front(&mut self, node: usize) {
+        if self.head == node {
+            return;
+        }
+        self.detach(node);
+        self.push_front(node);
+    }
+
+    fn push_front(&mut self, node: usize) {
+        self.nodes[node].prev = NIL;
+        self.nodes[node].next = self.head;
+        if self.head != NIL {
+            self.nodes[self.head].prev = node;
+        }
+        self.head = node;
+        if self.tail == NIL {
+            self.tail = node;
+        }
+    }
+
+    fn detach(&mut self, node: usize) {
+        let (prev, next) = (self.nodes[node].prev, self.nodes[node].next);
+        if prev != NIL {
+            self.nodes[prev].next = next;
+        } else {
+            self.head = next;
+        }
+        if next != NIL {
+            self.nodes[next].prev = prev;
+        } else {
+            self.tail = prev;
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let node = self.tail;
+        if node == NIL {
+            return;
+        }
+        self.detach(node);
+        self.index.remove(&self.nodes[node].key);
+        self.nodes[node].key.clear();
+        self.nodes[node].prediction.clear();
+        self.free.push(node);
+    }
+
+    fn hit_rate(&self) -> f64 {
+        let total = self.hits + self.misses;
+        if total == 0 {
+            0.0
+        } else {
+            self.hits as f64 / total as f64
+        }
+    }
 }
 
-// Run the model prediction
+// Run the model prediction over a batch of already-encoded contexts
 fn run_prediction(
     model: &CModule,
-    inputs: &[String],
-    vocab: &HashMap<String, i64>,
-    index_to_char: &[String],
+    contexts: &[Vec<i64>],
+    vocab: &Vocab,
     pad_token: i64,
+    buffer: &mut Vec<i64>,
+    decode: DecodeMode,
+    num_preds: i64,
+    temperature: f64,
+    top_p: f64,
+    rng_state: &mut u64,
+    device: Device,
+    metrics: &Metrics,
 ) -> Vec<String> {
     let start = Instant::now();
-    println!("Preparing input tensor for {} examples", inputs.len());
+    println!("Preparing input tensor for {} examples", contexts.len());
 
-    // Embed the input strings
-    let input_tensor = embed_strings(inputs, vocab, pad_token);
+    // Embed the already-encoded contexts
+    let input_tensor = embed_contexts(contexts, pad_token, buffer, device);
     println!("Input tensor prepared in {:.2?}", start.elapsed());
 
     // Run inference
@@ -183,64 +769,398 @@ fn run_prediction(
         .forward_ts(&[input_tensor])
         .expect("Model forward pass failed");
 
-    println!("Inference completed in {:.2?}", infer_start.elapsed());
+    let infer_elapsed = infer_start.elapsed();
+    metrics.inference_latency.observe(infer_elapsed);
+    println!("Inference completed in {:.2?}", infer_elapsed);
 
     // Post-processing
     let post_start = Instant::now();
-    let batch_size = inputs.len() as i64;
+    let batch_size = contexts.len() as i64;
+
+    // `--num_preds` comes straight from the CLI with nothing checking it against the loaded
+    // vocabulary. Requesting more predictions than the vocab has entries (easy to hit with a
+    // small/custom char vocab or a typo'd flag) would otherwise make `topk` fail below, so clamp
+    // it to what the vocab can actually supply (minus the masked-out pad token).
+    let num_preds = num_preds.min((vocab.size() - 1).max(1));
 
-    // Set PAD_TOKEN logits to -inf
+    // Set PAD_TOKEN logits to -inf so it can never be emitted, regardless of decode mode
     let mut logits_masked = logits.copy();
     logits_masked.get((.., pad_token)).fill_(-f64::INFINITY);
 
-    // Get top-3 predictions
-    let (_, indices) = logits_masked.topk(3, -1, true, true);
-    let indices_cpu = indices.to(Device::Cpu);
+    let results = match decode {
+        DecodeMode::Greedy => decode_greedy(&logits_masked, batch_size, num_preds, vocab),
+        DecodeMode::Temperature => decode_sampled(
+            &logits_masked,
+            batch_size,
+            num_preds,
+            vocab,
+            temperature,
+            None,
+            rng_state,
+        ),
+        DecodeMode::Nucleus => decode_sampled(
+            &logits_masked,
+            batch_size,
+            num_preds,
+            vocab,
+            1.0,
+            Some(top_p),
+            rng_state,
+        ),
+    };
+
+    let post_elapsed = post_start.elapsed();
+    metrics.post_processing_latency.observe(post_elapsed);
+    println!("Post-processing completed in {:.2?}", post_elapsed);
+    println!("Total prediction time: {:.2?}", start.elapsed());
+
+    results
+}
 
-    // Convert to 2D array and then to strings
+// Greedy/top-k decoding: take the `num_preds` highest-logit characters per row.
+fn decode_greedy(
+    logits_masked: &Tensor,
+    batch_size: i64,
+    num_preds: i64,
+    vocab: &Vocab,
+) -> Vec<String> {
+    let (_, indices) = logits_masked.topk(num_preds, -1, true, true);
+    let indices_cpu = indices.to(Device::Cpu);
     let indices_vec: Vec<i64> = indices_cpu.to_vec1::<i64>().unwrap_or_default();
 
     let mut results = Vec::new();
     for batch_idx in 0..batch_size as usize {
-        let start_idx = batch_idx * 3; // Each batch has 3 predictions
+        let start_idx = batch_idx * num_preds as usize;
         let mut prediction = String::new();
 
-        for i in 0..3 {
-            let char_idx = indices_vec[start_idx + i] as usize;
-            prediction.push_str(&index_to_char[char_idx]);
+        for i in 0..num_preds as usize {
+            let token_idx = indices_vec[start_idx + i];
+            prediction.push_str(&vocab.decode_token(token_idx));
         }
 
         results.push(prediction);
     }
+    results
+}
 
-    println!("Post-processing completed in {:.2?}", post_start.elapsed());
-    println!("Total prediction time: {:.2?}", start.elapsed());
+// Temperature-scaled sampling, optionally truncated to the nucleus (top-p) set. Softmaxes the
+// masked logits per row, truncates to the top-p prefix when `top_p` is given, then draws
+// `num_preds` distinct characters per row without replacement.
+fn decode_sampled(
+    logits_masked: &Tensor,
+    batch_size: i64,
+    num_preds: i64,
+    vocab: &Vocab,
+    temperature: f64,
+    top_p: Option<f64>,
+    rng_state: &mut u64,
+) -> Vec<String> {
+    let scaled = logits_masked / temperature.max(MIN_TEMPERATURE);
+    let probs_cpu = scaled.softmax(-1, Kind::Double).to(Device::Cpu);
+    let vocab_size = vocab.size();
+
+    // Pull the whole batch of probabilities out of the tensor in one bulk conversion instead of
+    // one FFI round-trip per (row, vocab-entry) pair. The latter is fine for the ~70-entry char
+    // vocab but becomes a real hot spot with a subword tokenizer's tens-of-thousands-entry vocab.
+    let probs_flat: Vec<f64> = probs_cpu.reshape(&[-1]).to_vec1::<f64>().unwrap_or_default();
+
+    let mut results = Vec::with_capacity(batch_size as usize);
+    for batch_idx in 0..batch_size {
+        let start = (batch_idx * vocab_size) as usize;
+        let row = probs_flat[start..start + vocab_size as usize].to_vec();
+
+        let mut truncated = row.clone();
+        if let Some(top_p) = top_p {
+            truncate_to_nucleus(&mut truncated, top_p);
+        }
 
+        let mut prediction = String::new();
+        for idx in sample_distinct(&truncated, &row, num_preds as usize, rng_state) {
+            prediction.push_str(&vocab.decode_token(idx as i64));
+        }
+        results.push(prediction);
+    }
     results
 }
 
-// Write predictions to file
-fn write_predictions(predictions: &[String], file_path: &str) -> io::Result<()> {
-    let start = Instant::now();
+// Zero out every probability outside the smallest prefix (taken in descending order) whose
+// cumulative mass first exceeds `top_p`, then renormalize what's kept.
+fn truncate_to_nucleus(row: &mut [f64], top_p: f64) {
+    let mut order: Vec<usize> = (0..row.len()).collect();
+    order.sort_by(|&a, &b| row[b].partial_cmp(&row[a]).unwrap());
 
-    let file = File::create(file_path)?;
-    let mut writer = BufWriter::with_capacity(8 * 1024 * 1024, file); // 8MB buffer
+    let mut cumulative = 0.0;
+    let mut cutoff = order.len();
+    for (rank, &idx) in order.iter().enumerate() {
+        cumulative += row[idx];
+        if cumulative > top_p {
+            cutoff = rank + 1;
+            break;
+        }
+    }
 
-    for pred in predictions {
-        writeln!(writer, "{}", pred)?;
+    let kept_mass: f64 = order[..cutoff].iter().map(|&idx| row[idx]).sum();
+    let mut truncated = vec![0.0; row.len()];
+    for &idx in &order[..cutoff] {
+        truncated[idx] = row[idx] / kept_mass;
     }
+    row.copy_from_slice(&truncated);
+}
 
-    // Make sure all data is written
-    writer.flush()?;
+// Draw `num_preds` distinct indices from `probs` via roulette-wheel sampling without
+// replacement: each draw removes its winner from the wheel, so a "duplicate" draw is
+// impossible by construction and the next-highest-probability remaining token is simply
+// whatever the shrunken wheel lands on next. `probs` may be a nucleus-truncated distribution
+// with fewer nonzero entries than `num_preds` (e.g. a small `--top_p`); once that pool runs dry,
+// top up from `fallback_probs` (the pre-truncation row) in descending-probability order so the
+// caller still gets `num_preds` distinct tokens whenever the vocabulary actually has that many.
+// Zero-probability entries (such as the masked-out pad token) are never used for the top-up.
+fn sample_distinct(probs: &[f64], fallback_probs: &[f64], num_preds: usize, rng_state: &mut u64) -> Vec<usize> {
+    let mut remaining: Vec<usize> = (0..probs.len()).filter(|&i| probs[i] > 0.0).collect();
+    let mut chosen = Vec::with_capacity(num_preds);
 
-    let duration = start.elapsed();
-    println!(
-        "Wrote {} predictions in {:.2?}",
-        predictions.len(),
-        duration
-    );
+    while chosen.len() < num_preds && !remaining.is_empty() {
+        let total: f64 = remaining.iter().map(|&i| probs[i]).sum();
+        let draw = next_random(rng_state) * total;
 
-    Ok(())
+        let mut acc = 0.0;
+        let mut pick_pos = remaining.len() - 1;
+        for (pos, &idx) in remaining.iter().enumerate() {
+            acc += probs[idx];
+            if draw <= acc {
+                pick_pos = pos;
+                break;
+            }
+        }
+
+        chosen.push(remaining.remove(pick_pos));
+    }
+
+    if chosen.len() < num_preds {
+        let mut already_chosen = vec![false; fallback_probs.len()];
+        for &idx in &chosen {
+            already_chosen[idx] = true;
+        }
+
+        let mut fallback_order: Vec<usize> = (0..fallback_probs.len())
+            .filter(|&i| !already_chosen[i] && fallback_probs[i] > 0.0)
+            .collect();
+        fallback_order
+            .sort_by(|&a, &b| fallback_probs[b].partial_cmp(&fallback_probs[a]).unwrap());
+
+        for idx in fallback_order {
+            if chosen.len() >= num_preds {
+                break;
+            }
+            chosen.push(idx);
+        }
+    }
+
+    chosen
+}
+
+// Minimal xorshift64 PRNG so sampling doesn't need an external `rand` dependency.
+fn next_random(state: &mut u64) -> f64 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    *state = x;
+    (x >> 11) as f64 / (1u64 << 53) as f64
+}
+
+// One token-budget batch of encoded contexts, tagged with its position in the input stream.
+// The reader and model stages run concurrently with the writer, so this sequence number is
+// how the writer restores input-line ordering no matter how the stages interleave.
+struct EncodedBatch {
+    seq: usize,
+    contexts: Vec<Vec<i64>>,
+}
+
+// The predictions for one `EncodedBatch`, still carrying its sequence number.
+struct PredictedBatch {
+    seq: usize,
+    predictions: Vec<String>,
+}
+
+// Busy-time bookkeeping for a single pipeline stage. Only time spent doing the stage's own
+// work counts as busy; time blocked on a channel send/receive (waiting on a neighboring
+// stage) does not, so `occupancy` reflects genuine I/O- vs compute-boundedness.
+#[derive(Default)]
+struct StageStats {
+    busy: Duration,
+}
+
+impl StageStats {
+    fn occupancy(&self, wall: Duration) -> f64 {
+        let wall_secs = wall.as_secs_f64();
+        if wall_secs <= 0.0 {
+            0.0
+        } else {
+            (self.busy.as_secs_f64() / wall_secs) * 100.0
+        }
+    }
+}
+
+// Reader stage: pulls lines from the input file and encodes them into a token-budget queue that
+// is flushed once it holds `batch_size` rows or `max_tokens` tokens (rows × CONTEXT_LENGTH),
+// sending each flushed batch to the model stage.
+// Blocks on `tx.send` (and so applies backpressure to itself) whenever the model stage is
+// behind, which keeps peak memory flat regardless of input file size.
+fn run_reader_stage(
+    lines: impl Iterator<Item = io::Result<String>>,
+    vocab: Arc<Vocab>,
+    pad_token: i64,
+    batch_size: i64,
+    max_tokens: i64,
+    tx: SyncSender<EncodedBatch>,
+    metrics: Arc<Metrics>,
+) -> io::Result<StageStats> {
+    let mut stats = StageStats::default();
+    let mut seq = 0usize;
+    let mut contexts: Vec<Vec<i64>> = Vec::with_capacity(batch_size.max(1) as usize);
+
+    let mut lines = lines;
+    loop {
+        // Reading the line from the file is this stage's own I/O work, so it counts as busy.
+        let work_start = Instant::now();
+        let line = match lines.next() {
+            Some(line) => line?,
+            None => break,
+        };
+        contexts.push(vocab.encode_context(&line, pad_token, &metrics));
+        metrics.record_example();
+        stats.busy += work_start.elapsed();
+
+        let token_budget = contexts.len() as i64 * CONTEXT_LENGTH;
+        if contexts.len() as i64 >= batch_size || token_budget >= max_tokens {
+            let flushed = std::mem::replace(&mut contexts, Vec::with_capacity(batch_size.max(1) as usize));
+            tx.send(EncodedBatch { seq, contexts: flushed })
+                .expect("model stage hung up");
+            seq += 1;
+        }
+    }
+    if !contexts.is_empty() {
+        tx.send(EncodedBatch { seq, contexts })
+            .expect("model stage hung up");
+    }
+
+    Ok(stats)
+}
+
+// Model stage: consumes encoded batches, serves cache hits directly (see `PredictionCache`
+// above) and runs cache misses through `run_prediction`, then forwards the combined
+// predictions to the writer stage tagged with the same sequence number. Owns the cache and
+// the decode RNG state for the run, since both are per-model-thread state.
+fn run_model_stage(
+    model: CModule,
+    vocab: Arc<Vocab>,
+    pad_token: i64,
+    cache_size: usize,
+    decode: DecodeMode,
+    num_preds: i64,
+    temperature: f64,
+    top_p: f64,
+    mut rng_state: u64,
+    device: Device,
+    rx: Receiver<EncodedBatch>,
+    tx: SyncSender<PredictedBatch>,
+    metrics: Arc<Metrics>,
+) -> (StageStats, PredictionCache) {
+    let mut stats = StageStats::default();
+    let mut cache = PredictionCache::new(cache_size);
+    let mut buffer: Vec<i64> = Vec::new();
+    let cacheable = matches!(decode, DecodeMode::Greedy);
+
+    for batch in rx {
+        let work_start = Instant::now();
+        metrics.record_batch(batch.contexts.len());
+
+        let mut results: Vec<Option<String>> = vec![None; batch.contexts.len()];
+        let mut miss_keys: Vec<Vec<i64>> = Vec::new();
+        let mut miss_indices: Vec<usize> = Vec::new();
+
+        for (i, key) in batch.contexts.into_iter().enumerate() {
+            let cached = if cacheable { cache.get(&key) } else { None };
+            match cached {
+                Some(prediction) => {
+                    metrics.record_cache_hit();
+                    results[i] = Some(prediction);
+                }
+                None => {
+                    if cacheable {
+                        metrics.record_cache_miss();
+                    }
+                    miss_indices.push(i);
+                    miss_keys.push(key);
+                }
+            }
+        }
+
+        if !miss_keys.is_empty() {
+            let predictions = run_prediction(
+                &model,
+                &miss_keys,
+                &vocab,
+                pad_token,
+                &mut buffer,
+                decode,
+                num_preds,
+                temperature,
+                top_p,
+                &mut rng_state,
+                device,
+                &metrics,
+            );
+            for ((idx, key), prediction) in miss_indices.into_iter().zip(miss_keys).zip(predictions) {
+                if cacheable {
+                    cache.insert(key, prediction.clone());
+                }
+                results[idx] = Some(prediction);
+            }
+        }
+
+        let predictions: Vec<String> = results
+            .into_iter()
+            .map(|p| p.expect("every batch row should be resolved by a cache hit or the model"))
+            .collect();
+        stats.busy += work_start.elapsed();
+
+        tx.send(PredictedBatch { seq: batch.seq, predictions })
+            .expect("writer stage hung up");
+    }
+
+    (stats, cache)
+}
+
+// Writer stage: drains predicted batches and writes them out in `seq` order, holding any batch
+// that arrives ahead of its turn until the batches before it have been written. With the single
+// reader and single model stage connected by FIFO channels, batches arrive in order already.
+fn run_writer_stage(
+    mut writer: impl Write,
+    rx: Receiver<PredictedBatch>,
+) -> io::Result<(StageStats, usize)> {
+    let mut stats = StageStats::default();
+    let mut total = 0usize;
+    let mut next_seq = 0usize;
+    let mut pending: HashMap<usize, Vec<String>> = HashMap::new();
+
+    for batch in rx {
+        pending.insert(batch.seq, batch.predictions);
+
+        while let Some(predictions) = pending.remove(&next_seq) {
+            let work_start = Instant::now();
+            for prediction in &predictions {
+                writeln!(writer, "{}", prediction)?;
+            }
+            total += predictions.len();
+            stats.busy += work_start.elapsed();
+            next_seq += 1;
+        }
+    }
+
+    writer.flush()?;
+    Ok((stats, total))
 }
 
 fn main() -> io::Result<()> {
@@ -254,6 +1174,30 @@ fn main() -> io::Result<()> {
     println!("  Test output: {}", args.test_output);
     println!("  Timing enabled: {}", args.time);
     println!("  Using TorchScript: {}", args.torchscript);
+    println!("  Batch size: {}", args.batch_size);
+    println!("  Max tokens per batch: {}", args.max_tokens);
+    println!("  Cache size: {}", args.cache_size);
+    println!("  Num predictions: {}", args.num_preds);
+    println!("  Temperature: {}", args.temperature);
+    println!("  Top-p: {}", args.top_p);
+    println!(
+        "  Tokenizer: {}",
+        args.tokenizer_path.as_deref().unwrap_or("<char-level>")
+    );
+
+    let device = resolve_device(args.device);
+    println!("  Device: {:?}", device);
+
+    // Metrics are always collected so the pipeline stages don't need to special-case them
+    // being absent; the HTTP endpoint is only started when `--metrics_port` is given.
+    let metrics = Arc::new(Metrics::new());
+    match args.metrics_port {
+        Some(port) => {
+            spawn_metrics_server(port, Arc::clone(&metrics))?;
+            println!("  Metrics: http://127.0.0.1:{}/metrics", port);
+        }
+        None => println!("  Metrics: disabled"),
+    }
 
     let total_start = Instant::now();
 
@@ -265,10 +1209,25 @@ fn main() -> io::Result<()> {
     };
     let vocab_path = format!("{}/char_to_index.json", args.work_dir);
 
-    // Step 2: Load vocabulary
+    // Step 2: Load the vocabulary. A subword tokenizer takes over from the default
+    // character-level vocab whenever `--tokenizer` points at a HuggingFace tokenizer.json.
     let vocab_start = Instant::now();
-    let (vocab, index_to_char) = load_vocab(&vocab_path)?;
-    let pad_token = *vocab.get(" ").expect("No padding token in vocabulary");
+    let vocab = match &args.tokenizer_path {
+        Some(tokenizer_path) => {
+            println!("Loading subword tokenizer from {}", tokenizer_path);
+            let tokenizer = Tokenizer::from_file(tokenizer_path)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+            Vocab::Subword { tokenizer }
+        }
+        None => {
+            let (char_to_index, index_to_char) = load_char_vocab(&vocab_path)?;
+            Vocab::Char {
+                char_to_index,
+                index_to_char,
+            }
+        }
+    };
+    let pad_token = vocab.pad_token();
 
     if args.time {
         println!("Vocabulary loaded in {:.2?}", vocab_start.elapsed());
@@ -278,7 +1237,7 @@ fn main() -> io::Result<()> {
     let model_start = Instant::now();
     println!("Loading model from {}", model_path);
 
-    let model = CModule::load(&model_path).map_err(|e| {
+    let model = CModule::load_on_device(&model_path, device).map_err(|e| {
         io::Error::new(io::ErrorKind::Other, format!("Failed to load model: {}", e))
     })?;
 
@@ -286,28 +1245,89 @@ fn main() -> io::Result<()> {
         println!("Model loaded in {:.2?}", model_start.elapsed());
     }
 
-    // Step 4: Load test data
-    let data_start = Instant::now();
-    let test_input = load_test_input(&args.test_data);
+    // Step 4: Run load, inference, and write as three concurrent pipeline stages connected by
+    // bounded channels, so the GPU isn't idle during file I/O and vice versa. The reader
+    // blocks on a full channel when the model stage is behind, which is the backpressure that
+    // keeps peak memory flat; the writer reassembles batches by sequence number since the
+    // stages run independently of each other.
+    let predict_start = Instant::now();
+    let lines = load_test_input(&args.test_data)?;
 
-    if args.time {
-        println!("Data loading took {:.2?}", data_start.elapsed());
-    }
+    let output_file = File::create(&args.test_output)?;
+    let writer = BufWriter::with_capacity(8 * 1024 * 1024, output_file); // 8MB buffer
+    let rng_state = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos() as u64)
+        .unwrap_or(1)
+        | 1; // xorshift64 can't recover from a zero state
 
-    // Step 5: Run prediction
-    let predict_start = Instant::now();
-    let predictions = run_prediction(&model, &test_input, &vocab, &index_to_char, pad_token);
+    let vocab = Arc::new(vocab);
+    let (encoded_tx, encoded_rx) = sync_channel::<EncodedBatch>(PIPELINE_CHANNEL_CAPACITY);
+    let (predicted_tx, predicted_rx) = sync_channel::<PredictedBatch>(PIPELINE_CHANNEL_CAPACITY);
 
-    if args.time {
-        println!("Prediction took {:.2?}", predict_start.elapsed());
-    }
+    let reader_vocab = Arc::clone(&vocab);
+    let reader_metrics = Arc::clone(&metrics);
+    let reader_batch_size = args.batch_size;
+    let reader_max_tokens = args.max_tokens;
+    let reader_handle = thread::spawn(move || {
+        run_reader_stage(
+            lines,
+            reader_vocab,
+            pad_token,
+            reader_batch_size,
+            reader_max_tokens,
+            encoded_tx,
+            reader_metrics,
+        )
+    });
 
-    // Step 6: Write predictions to output file
-    let write_start = Instant::now();
-    write_predictions(&predictions, &args.test_output)?;
+    let model_vocab = Arc::clone(&vocab);
+    let model_metrics = Arc::clone(&metrics);
+    let model_cache_size = args.cache_size;
+    let model_decode = args.decode;
+    let model_num_preds = args.num_preds;
+    let model_temperature = args.temperature;
+    let model_top_p = args.top_p;
+    let model_handle = thread::spawn(move || {
+        run_model_stage(
+            model,
+            model_vocab,
+            pad_token,
+            model_cache_size,
+            model_decode,
+            model_num_preds,
+            model_temperature,
+            model_top_p,
+            rng_state,
+            device,
+            encoded_rx,
+            predicted_tx,
+            model_metrics,
+        )
+    });
 
+    let writer_handle = thread::spawn(move || run_writer_stage(writer, predicted_rx));
+
+    let reader_stats = reader_handle.join().expect("reader thread panicked")?;
+    let (model_stats, cache) = model_handle.join().expect("model thread panicked");
+    let (writer_stats, total_predicted) = writer_handle.join().expect("writer thread panicked")?;
+
+    let pipeline_wall = predict_start.elapsed();
+    println!("Wrote {} predictions", total_predicted);
     if args.time {
-        println!("Writing output took {:.2?}", write_start.elapsed());
+        println!("Prediction took {:.2?}", pipeline_wall);
+        println!(
+            "Cache hit rate: {:.1}% ({} hits / {} lookups)",
+            cache.hit_rate() * 100.0,
+            cache.hits,
+            cache.hits + cache.misses
+        );
+        println!(
+            "Stage occupancy — reader: {:.1}%, model: {:.1}%, writer: {:.1}%",
+            reader_stats.occupancy(pipeline_wall),
+            model_stats.occupancy(pipeline_wall),
+            writer_stats.occupancy(pipeline_wall)
+        );
     }
 
     // Report total time
@@ -319,3 +1339,116 @@ fn main() -> io::Result<()> {
     println!("Completed successfully");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncate_to_nucleus_keeps_smallest_prefix_exceeding_top_p() {
+        let mut row = vec![0.5, 0.3, 0.1, 0.1];
+        truncate_to_nucleus(&mut row, 0.7);
+
+        // Cumulative mass crosses 0.7 after the top two entries (0.5 + 0.3 = 0.8), so only
+        // those survive, renormalized to sum to 1.
+        assert_eq!(row[2], 0.0);
+        assert_eq!(row[3], 0.0);
+        assert!((row[0] - 0.625).abs() < 1e-9);
+        assert!((row[1] - 0.375).abs() < 1e-9);
+        assert!((row.iter().sum::<f64>() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn truncate_to_nucleus_cumulative_equal_to_top_p_is_not_a_cutoff() {
+        // The cutoff check is `cumulative > top_p`, so landing exactly on top_p after an entry
+        // must not stop there yet.
+        let mut row = vec![0.7, 0.3];
+        truncate_to_nucleus(&mut row, 0.7);
+
+        assert!((row[0] - 0.7).abs() < 1e-9);
+        assert!((row[1] - 0.3).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sample_distinct_never_repeats_an_index() {
+        let probs = vec![0.4, 0.3, 0.2, 0.1];
+        let mut rng_state = 0xdead_beefu64;
+        let chosen = sample_distinct(&probs, &probs, 3, &mut rng_state);
+
+        assert_eq!(chosen.len(), 3);
+        let mut seen = chosen.clone();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), chosen.len());
+    }
+
+    #[test]
+    fn sample_distinct_tops_up_from_fallback_when_primary_pool_runs_dry() {
+        // `probs` mimics a nucleus-truncated row with only one nonzero entry (index 1), but the
+        // untruncated `fallback_probs` has enough mass elsewhere to still supply 3 distinct
+        // tokens. The request guarantees `num_preds` distinct tokens whenever the vocabulary
+        // actually has that many, so the primary pool running dry must not shrink the result.
+        let probs = vec![0.0, 1.0, 0.0, 0.0];
+        let fallback_probs = vec![0.1, 0.6, 0.2, 0.1];
+        let mut rng_state = 42;
+        let chosen = sample_distinct(&probs, &fallback_probs, 3, &mut rng_state);
+
+        assert_eq!(chosen.len(), 3);
+        assert!(chosen.contains(&1));
+        let mut seen = chosen.clone();
+        seen.sort_unstable();
+        seen.dedup();
+        assert_eq!(seen.len(), chosen.len());
+    }
+
+    #[test]
+    fn sample_distinct_returns_fewer_than_requested_when_vocab_truly_lacks_that_many_tokens() {
+        // Only two tokens exist anywhere (indices 1 and 3), so 3 distinct draws can never be
+        // satisfied even with the fallback top-up.
+        let probs = vec![0.0, 0.4, 0.0, 0.6];
+        let mut rng_state = 99;
+        let chosen = sample_distinct(&probs, &probs, 3, &mut rng_state);
+
+        let mut seen = chosen.clone();
+        seen.sort_unstable();
+        assert_eq!(seen, vec![1, 3]);
+    }
+
+    #[test]
+    fn prediction_cache_evicts_least_recently_used_on_overflow() {
+        let mut cache = PredictionCache::new(2);
+        cache.insert(vec![1], "a".to_string());
+        cache.insert(vec![2], "b".to_string());
+        cache.insert(vec![3], "c".to_string());
+
+        // Capacity 2, inserted in order 1, 2, 3 with no intervening reads: 1 is the
+        // least-recently-used entry and should be the one evicted.
+        assert_eq!(cache.get(&[1]), None);
+        assert_eq!(cache.get(&[2]), Some("b".to_string()));
+        assert_eq!(cache.get(&[3]), Some("c".to_string()));
+    }
+
+    #[test]
+    fn prediction_cache_get_refreshes_recency() {
+        let mut cache = PredictionCache::new(2);
+        cache.insert(vec![1], "a".to_string());
+        cache.insert(vec![2], "b".to_string());
+
+        // Touching key 1 makes it most-recently-used, so the next overflow should evict key 2
+        // instead of key 1.
+        assert_eq!(cache.get(&[1]), Some("a".to_string()));
+        cache.insert(vec![3], "c".to_string());
+
+        assert_eq!(cache.get(&[1]), Some("a".to_string()));
+        assert_eq!(cache.get(&[2]), None);
+        assert_eq!(cache.get(&[3]), Some("c".to_string()));
+    }
+
+    #[test]
+    fn prediction_cache_zero_capacity_never_stores() {
+        let mut cache = PredictionCache::new(0);
+        cache.insert(vec![1], "a".to_string());
+
+        assert_eq!(cache.get(&[1]), None);
+    }
+}